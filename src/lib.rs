@@ -4,11 +4,17 @@ extern crate r2d2;
 extern crate r2d2_diesel;
 
 use iron::prelude::*;
-use iron::{typemap, BeforeMiddleware};
+use iron::{status, typemap, BeforeMiddleware, IronError};
 
+use std::collections::HashMap;
+use std::env;
 use std::error::Error;
+use std::fmt;
 use std::sync::Arc;
 
+/// The name a `DieselMiddleware` registers its pool under when none is given explicitly.
+const DEFAULT_DB_NAME: &'static str = "default";
+
 /// The type of the pool stored in `DieselMiddleware`.
 pub type DieselPool<T: diesel::Connection> = Arc<r2d2::Pool<r2d2_diesel::ConnectionManager<T>>>;
 
@@ -18,12 +24,53 @@ pub type DieselPooledConnection<T: diesel::Connection> = r2d2::PooledConnection<
 pub struct DieselMiddleware<T: 'static + diesel::Connection> {
   /// A pool of diesel connections that are shared between requests.
   pub pool: DieselPool<T>,
+  /// The name this pool is registered under. Defaults to `DEFAULT_DB_NAME`; set a
+  /// different name with `named` to register more than one pool for the same
+  /// connection type (e.g. a primary and a read replica).
+  name: String,
 }
 
-pub struct Value<T: 'static + diesel::Connection>(DieselPool<T>);
+/// A request's diesel pools, keyed by name. Several `DieselMiddleware<T>` instances can
+/// be chained in the same `Chain`; each `before` hook merges its pool into this map
+/// instead of overwriting it, so every named pool stays reachable.
+pub struct Value<T: 'static + diesel::Connection>(HashMap<String, DieselPool<T>>);
 
 impl<T: diesel::Connection> typemap::Key for DieselMiddleware<T> { type Value = Value<T>; }
 
+/// Error returned when a request tries to fetch a connection but no
+/// `DieselMiddleware` was ever registered with the `Chain`.
+#[derive(Debug)]
+pub struct MiddlewareNotRegisteredError;
+
+impl fmt::Display for MiddlewareNotRegisteredError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DieselMiddleware has not been registered with Iron")
+    }
+}
+
+impl Error for MiddlewareNotRegisteredError {
+    fn description(&self) -> &str {
+        "DieselMiddleware has not been registered with Iron"
+    }
+}
+
+/// Error returned when `named_db_conn` (or `try_named_db_conn`) is called with a name
+/// that no registered `DieselMiddleware` was constructed with.
+#[derive(Debug)]
+pub struct UnknownDatabaseError(pub String);
+
+impl fmt::Display for UnknownDatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no DieselMiddleware registered for database \"{}\"", self.0)
+    }
+}
+
+impl Error for UnknownDatabaseError {
+    fn description(&self) -> &str {
+        "no DieselMiddleware registered for the requested database name"
+    }
+}
+
 impl<T: diesel::Connection> DieselMiddleware<T> {
 
     /// Creates a new pooled connection to the given sql server. The URL is in the format:
@@ -46,13 +93,92 @@ impl<T: diesel::Connection> DieselMiddleware<T> {
 
         Ok(DieselMiddleware {
           pool: Arc::new(pool),
+          name: DEFAULT_DB_NAME.to_owned(),
         })
     }
+
+    /// Registers this pool under `name` instead of `DEFAULT_DB_NAME`, so that multiple
+    /// `DieselMiddleware` instances for different databases (e.g. a primary and a read
+    /// replica) can be chained in the same `Chain` and selected later with
+    /// `named_db_conn`/`try_named_db_conn`.
+    pub fn named(mut self, name: &str) -> Self {
+        self.name = name.to_owned();
+        self
+    }
+
+    /// Creates a new connection pool and runs any pending diesel migrations against it
+    /// before returning. `migrations` is typically a thin wrapper around
+    /// `diesel::migrations::run_pending_migrations`, or the function generated by
+    /// `embed_migrations!`, so that a `migrations/` directory can be embedded in the
+    /// binary and applied without a separate `diesel migration run` step.
+    ///
+    /// Returns `Err(err)` if connecting to the database or running the migrations fails.
+    pub fn new_with_migrations<F>(connection_str: &str, migrations: F) -> Result<DieselMiddleware<T>, Box<Error>>
+      where F: FnOnce(&T) -> Result<(), Box<Error>>
+    {
+        Self::new_with_migrations_and_config(connection_str, r2d2::Config::default(), migrations)
+    }
+
+    /// Creates a new connection pool with your own r2d2 configuration and runs any pending
+    /// diesel migrations against it before returning. See `new_with_migrations` for details.
+    pub fn new_with_migrations_and_config<F>(
+      connection_str: &str,
+      config: r2d2::Config<T, r2d2_diesel::Error>,
+      migrations: F
+    ) -> Result<DieselMiddleware<T>, Box<Error>>
+      where F: FnOnce(&T) -> Result<(), Box<Error>>
+    {
+        let middleware = try!(Self::new_with_config(connection_str, config));
+
+        {
+            let conn = try!(middleware.pool.get());
+            try!(migrations(&*conn));
+        }
+
+        Ok(middleware)
+    }
+
+    /// Creates a new connection pool from environment variables, reading the connection
+    /// string from `DATABASE_URL` and, if present, using `MAX_DB_CONNECTIONS` to set
+    /// `r2d2::Config::pool_size`. Populate the process environment however you like
+    /// (e.g. with a `.env` file loaded by the `dotenv` crate) before calling this.
+    ///
+    /// Returns `Err(err)` if `DATABASE_URL` is unset, `MAX_DB_CONNECTIONS` is set but
+    /// isn't a valid number, or connecting to the database fails.
+    pub fn from_env() -> Result<DieselMiddleware<T>, Box<Error>> {
+        let connection_str = try!(env::var("DATABASE_URL"));
+
+        let mut config = r2d2::Config::default();
+        if let Ok(max_connections) = env::var("MAX_DB_CONNECTIONS") {
+            config = config.pool_size(try!(max_connections.parse::<u32>()));
+        }
+
+        Self::new_with_config(&connection_str, config)
+    }
+
+    /// Creates a new connection pool that runs `customizer` once per newly-created
+    /// connection, e.g. to run setup SQL such as `PRAGMA foreign_keys = ON` on SQLite or
+    /// `SET statement_timeout` on Postgres, or to reject a connection that fails a
+    /// check when it is created. The customizer is not run again when an existing
+    /// connection is checked out of the pool. See `r2d2::CustomizeConnection` for details.
+    pub fn new_with_customizer(
+      connection_str: &str,
+      customizer: Box<r2d2::CustomizeConnection<T, r2d2_diesel::Error>>
+    ) -> Result<DieselMiddleware<T>, Box<Error>> {
+        let config = r2d2::Config::default().connection_customizer(customizer);
+        Self::new_with_config(connection_str, config)
+    }
 }
 
 impl<T: diesel::Connection> BeforeMiddleware for DieselMiddleware<T> {
     fn before(&self, req: &mut Request) -> IronResult<()> {
-        req.extensions.insert::<DieselMiddleware<T>>(Value(self.pool.clone()));
+        let mut pools = req.extensions.remove::<DieselMiddleware<T>>()
+            .map(|Value(pools)| pools)
+            .unwrap_or_else(HashMap::new);
+
+        pools.insert(self.name.clone(), self.pool.clone());
+
+        req.extensions.insert::<DieselMiddleware<T>>(Value(pools));
         Ok(())
     }
 }
@@ -80,13 +206,55 @@ pub trait DieselReqExt<T: 'static + diesel::Connection> {
   /// **Panics** if a `DieselMiddleware` has not been registered with Iron, or if retrieving
   /// a connection to the database times out.
   fn db_conn(&self) -> r2d2::PooledConnection<r2d2_diesel::ConnectionManager<T>>;
+
+  /// Returns a pooled connection to the sql database, without panicking.
+  ///
+  /// Returns `Err` with `status::InternalServerError` if a `DieselMiddleware` has not
+  /// been registered with Iron, and `Err` with `status::ServiceUnavailable` if the pool
+  /// could not hand out a connection (e.g. it is exhausted or the checkout timed out).
+  fn try_db_conn(&self) -> IronResult<DieselPooledConnection<T>>;
+
+  /// Returns a pooled connection to the database registered under `name` (see
+  /// `DieselMiddleware::named`). The connection is returned to the pool when the pooled
+  /// connection is dropped.
+  ///
+  /// **Panics** if no `DieselMiddleware` has been registered under `name`, or if
+  /// retrieving a connection to the database times out.
+  fn named_db_conn(&self, name: &str) -> r2d2::PooledConnection<r2d2_diesel::ConnectionManager<T>>;
+
+  /// Returns a pooled connection to the database registered under `name`, without
+  /// panicking.
+  ///
+  /// Returns `Err` with `status::InternalServerError` if no `DieselMiddleware` has been
+  /// registered under `name`, and `Err` with `status::ServiceUnavailable` if the pool
+  /// could not hand out a connection.
+  fn try_named_db_conn(&self, name: &str) -> IronResult<DieselPooledConnection<T>>;
 }
 
 impl<'a, 'b, T: 'static + diesel::Connection> DieselReqExt<T> for Request<'a, 'b> {
   fn db_conn(&self) -> r2d2::PooledConnection<r2d2_diesel::ConnectionManager<T>> {
-    let poll_value = self.extensions.get::<DieselMiddleware<T>>().unwrap();
-    let &Value(ref poll) = poll_value;
+    self.try_db_conn().unwrap()
+  }
+
+  fn try_db_conn(&self) -> IronResult<DieselPooledConnection<T>> {
+    self.try_named_db_conn(DEFAULT_DB_NAME)
+  }
+
+  fn named_db_conn(&self, name: &str) -> r2d2::PooledConnection<r2d2_diesel::ConnectionManager<T>> {
+    self.try_named_db_conn(name).unwrap()
+  }
+
+  fn try_named_db_conn(&self, name: &str) -> IronResult<DieselPooledConnection<T>> {
+    let &Value(ref pools) = try!(
+      self.extensions.get::<DieselMiddleware<T>>()
+        .ok_or_else(|| IronError::new(MiddlewareNotRegisteredError, status::InternalServerError))
+    );
+
+    let pool = try!(
+      pools.get(name)
+        .ok_or_else(|| IronError::new(UnknownDatabaseError(name.to_owned()), status::InternalServerError))
+    );
 
-    return poll.get().unwrap();
+    pool.get().map_err(|err| IronError::new(err, status::ServiceUnavailable))
   }
 }